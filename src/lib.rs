@@ -0,0 +1,39 @@
+extern crate rand;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
+
+mod mep;
+mod runner;
+mod speciation;
+
+pub use mep::Mep;
+pub use runner::{GenerationStats, Population, Runner, RunnerConfig, StagnationConfig, StagnationResponse};
+pub use speciation::{compatibility_distance, speciate, Species, SpeciationConfig};
+
+use rand::Rng;
+
+/*
+A GeneticAlgorithm is capable of producing a child genome from two parent genomes via `mate`, and of randomly
+perturbing its own genome via `mutate`. Every evolvable genome representation in this crate implements this trait.
+*/
+pub trait GeneticAlgorithm<Ins> {
+    fn mate<R>(parents: (&Self, &Self), rng: &mut R) -> Self where R: Rng;
+    fn mutate<F, R>(&mut self, rng: &mut R, mutator: F) where F: FnMut(&mut Ins), R: Rng;
+}
+
+/*
+A FunctionalAlgorithm can be executed against a slice of inputs and a processor closure to lazily produce the
+requested number of outputs. The iterator it returns yields one output at a time, computing only the opcodes that
+are actually reachable from the requested outputs. `Iter` borrows from both `self` and `inputs`, so the trait is
+parameterized over their lifetimes explicitly rather than eliding them.
+*/
+pub trait FunctionalAlgorithm<'a, 'b, Ins, Input: 'b, Intermediate, Output, Iter, F>
+    where Iter: Iterator<Item=Output>
+{
+    fn execute(&'a self, inputs: &'b [Input], outputs: usize, processor: F) -> Iter;
+}
@@ -1,15 +1,21 @@
 use std::collections::BTreeSet;
 use std::cmp;
 use rand::Rng;
+use rand::distributions::{IndependentSample, Weighted, WeightedChoice};
 use std::ops::Range;
 use std::iter::Rev;
 use super::{GeneticAlgorithm, FunctionalAlgorithm};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer};
+#[cfg(feature = "serde")]
+use serde::de::Error;
 
 /*
 Defines an opcode for the Mep. Every opcode contains an instruction and two parameter indices. These specify which
 previous opcodes produced the result required as inputs to this opcode. These parameters can also come from the inputs
 to the program, which sequentially preceed the internal instructions.
 */
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 struct Opcode<Ins> {
     instruction: Ins,
     first: usize,
@@ -29,6 +35,7 @@ impl<Ins> Clone for Opcode<Ins> where Ins: Clone {
 /*
 A multi-expression program represented using a series of operations that can reuse results of previous operations.
 */
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct Mep<Ins> {
     program: Vec<Opcode<Ins>>,
     unit_mutate_size: usize,
@@ -36,10 +43,46 @@ pub struct Mep<Ins> {
     inputs: usize,
 }
 
+/*
+Mep is deserialized by hand rather than derived so that the `first`/`second` invariant every opcode relies on
+(each must index only opcodes or inputs that precede it) can be checked once, here, rather than letting a
+corrupted file panic later inside `ResultIterator`.
+*/
+#[cfg(feature = "serde")]
+impl<'de, Ins> Deserialize<'de> for Mep<Ins> where Ins: Deserialize<'de> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        #[derive(Deserialize)]
+        struct RawMep<Ins> {
+            program: Vec<Opcode<Ins>>,
+            unit_mutate_size: usize,
+            crossover_points: usize,
+            inputs: usize,
+        }
+
+        let raw = RawMep::deserialize(deserializer)?;
+
+        for (index, op) in raw.program.iter().enumerate() {
+            let bound = index + raw.inputs;
+            if op.first >= bound || op.second >= bound {
+                return Err(D::Error::custom(format!(
+                    "opcode {} references parameter out of bounds (first={}, second={}, must each be < {})",
+                    index, op.first, op.second, bound)));
+            }
+        }
+
+        Ok(Mep{
+            program: raw.program,
+            unit_mutate_size: raw.unit_mutate_size,
+            crossover_points: raw.crossover_points,
+            inputs: raw.inputs,
+        })
+    }
+}
+
 struct ResultIterator<'a, 'b, Ins: 'a, Param: 'b, F> where F: Fn(&Ins, Param, Param) -> Param {
     mep: &'a Mep<Ins>,
     buff: Vec<Option<Param>>,
-    solve_iter: Rev<usize>,
+    solve_iter: Rev<Range<usize>>,
     inputs: &'b [Param],
     processor: F,
 }
@@ -66,9 +109,168 @@ impl<Ins> Mep<Ins> {
                         second: rng.gen_range(0, index + inputs)
                     }
                 ).collect(),
-            unit_mutate_size: unit_mutate_size,
-            crossover_points: crossover_points,
-            inputs: inputs
+            unit_mutate_size,
+            crossover_points,
+            inputs
+        }
+    }
+
+    /*
+    The number of opcodes in the program. Exposed crate-internally so other subsystems (speciation, effective-code
+    extraction) can reason about genome size without reaching into `program` directly.
+    */
+    pub(crate) fn len(&self) -> usize {
+        self.program.len()
+    }
+
+    /*
+    The `first`/`second` parameter indices of the opcode at `index`.
+    */
+    pub(crate) fn opcode_params(&self, index: usize) -> (usize, usize) {
+        (self.program[index].first, self.program[index].second)
+    }
+
+    /*
+    The instruction of the opcode at `index`.
+    */
+    pub(crate) fn instruction(&self, index: usize) -> &Ins {
+        &self.program[index].instruction
+    }
+
+    /*
+    The current unit_mutate_size, i.e. the expected number of opcodes between mutation events. Exposed
+    crate-internally so the runner's stagnation recovery can temporarily raise the mutation rate.
+    */
+    pub(crate) fn unit_mutate_size(&self) -> usize {
+        self.unit_mutate_size
+    }
+
+    /*
+    Overrides unit_mutate_size directly, bypassing the gradual drift `mutate` normally applies to it.
+    */
+    pub(crate) fn set_unit_mutate_size(&mut self, value: usize) {
+        self.unit_mutate_size = value;
+    }
+
+    /*
+    Prunes every opcode that is not reachable from the requested `outputs`, the same reachability `execute`
+    already walks via `ResultIterator::solve`, and rebuilds a dense program out of what remains with
+    `first`/`second` remapped to the new indices. The leading `inputs` positions are untouched since they are
+    never part of `program`. Returns the pruned genome along with the number of opcodes that were removed.
+    */
+    pub fn effective(&self, outputs: usize) -> (Mep<Ins>, usize) where Ins: Clone {
+        assert!(outputs <= self.program.len());
+
+        let mut reachable = vec![false; self.program.len()];
+        let mut stack: Vec<usize> = (self.program.len() - outputs..self.program.len()).collect();
+        while let Some(index) = stack.pop() {
+            if reachable[index] {
+                continue;
+            }
+            reachable[index] = true;
+            let op = &self.program[index];
+            for &param in &[op.first, op.second] {
+                if param >= self.inputs {
+                    stack.push(param - self.inputs);
+                }
+            }
+        }
+
+        let mut remap = vec![None; self.program.len()];
+        let mut next_index = 0;
+        for (old_index, &is_reachable) in reachable.iter().enumerate() {
+            if is_reachable {
+                remap[old_index] = Some(next_index);
+                next_index += 1;
+            }
+        }
+
+        let program: Vec<Opcode<Ins>> = reachable.iter().enumerate()
+            .filter(|&(_, &is_reachable)| is_reachable)
+            .map(|(old_index, _)| {
+                let op = &self.program[old_index];
+                let remap_param = |param: usize| {
+                    if param < self.inputs {
+                        param
+                    } else {
+                        self.inputs + remap[param - self.inputs].expect("reachable opcode depends on an unreachable one")
+                    }
+                };
+                Opcode{
+                    instruction: op.instruction.clone(),
+                    first: remap_param(op.first),
+                    second: remap_param(op.second),
+                }
+            })
+            .collect();
+
+        let removed = self.program.len() - program.len();
+        (Mep{
+            program,
+            unit_mutate_size: self.unit_mutate_size,
+            crossover_points: self.crossover_points,
+            inputs: self.inputs,
+        }, removed)
+    }
+
+    /*
+    Weighted variant of `mutate`. Instead of handing instruction mutation off to an opaque mutator, this builds a
+    histogram of how often each entry of `palette` already occurs in the genome, adds `smoothing` to every count
+    so instructions that have not appeared yet still have a chance of being drawn, and replaces instructions with
+    a `WeightedChoice` built over that histogram. The histogram is rebuilt on every call, so the distribution
+    co-adapts with the genome as it evolves instead of staying fixed to the palette's initial frequencies.
+    */
+    pub fn mutate_weighted<R, Eq>(&mut self, rng: &mut R, palette: &[Ins], instructions_equal: Eq, smoothing: f64)
+        where R: Rng, Eq: Fn(&Ins, &Ins) -> bool, Ins: Clone
+    {
+        assert!(!palette.is_empty());
+
+        let mut weights: Vec<Weighted<usize>> = palette.iter().enumerate()
+            .map(|(palette_index, candidate)| {
+                let occurrences = self.program.iter()
+                    .filter(|op| instructions_equal(&op.instruction, candidate))
+                    .count();
+                let weight = ((occurrences as f64 + smoothing) * 1000.0).round() as u32;
+                Weighted{weight: cmp::max(weight, 1), item: palette_index}
+            })
+            .collect();
+        let chooser = WeightedChoice::new(&mut weights);
+
+        //Mutate unit_mutate_size
+        if rng.gen_range(0, self.unit_mutate_size) == 0 {
+            //Make it possibly go up or down by 1
+            match rng.gen_range(0, 2) {
+                0 => self.unit_mutate_size += 1,
+                1 => if self.unit_mutate_size > 1 {self.unit_mutate_size -= 1},
+                _ => unreachable!(),
+            }
+        }
+        //Mutate crossover_points
+        if rng.gen_range(0, self.unit_mutate_size) == 0 {
+            //Make it possibly go up or down by 1
+            match rng.gen_range(0, 2) {
+                0 => self.crossover_points += 1,
+                1 => if self.crossover_points > 1 {self.crossover_points -= 1},
+                _ => unreachable!(),
+            }
+        }
+
+        //Mutate the instructions, drawing replacements from the weighted palette instead of calling a mutator
+        loop {
+            //Choose a random location in the instructions and then add a random value up to the unit_mutate_size
+            let choice = rng.gen_range(0, self.program.len()) + rng.gen_range(0, self.unit_mutate_size);
+            //Whenever we choose a location outside the vector reject the choice and end mutation
+            if choice >= self.program.len() {
+                break;
+            }
+            let op = &mut self.program[choice];
+            //Randomly mutate only one of the things contained here
+            match rng.gen_range(0, 3) {
+                0 => op.instruction = palette[chooser.ind_sample(rng)].clone(),
+                1 => op.first = rng.gen_range(0, choice + self.inputs),
+                2 => op.second = rng.gen_range(0, choice + self.inputs),
+                _ => unreachable!(),
+            }
         }
     }
 }
@@ -162,12 +364,13 @@ impl<Ins> GeneticAlgorithm<Ins> for Mep<Ins>
             if choice >= self.program.len() {
                 break;
             }
-            let op = &self.program[choice];
+            let op = &mut self.program[choice];
             //Randomly mutate only one of the things contained here
             match rng.gen_range(0, 3) {
                 0 => mutator(&mut op.instruction),
                 1 => op.first = rng.gen_range(0, choice + self.inputs),
                 2 => op.second = rng.gen_range(0, choice + self.inputs),
+                _ => unreachable!(),
             }
         }
     }
@@ -180,56 +383,53 @@ results from previous calls of the processor closure. Also, the output is also d
 closure. Due to this restriction, all of these types must be the same for Mep, thus FunctionalAlgorithm is only
 implemented then.
 */
-impl<'a, 'b, Ins, Param, F> FunctionalAlgorithm<Ins, Param, Param, Param, ResultIterator<'a, 'b, Ins, Param, F>, F> for Mep<Ins>
-    where F: Fn(&Ins, Param, Param) -> Param {
-    fn execute(&self, inputs: &[Param],
+impl<'a, 'b, Ins, Param, F> FunctionalAlgorithm<'a, 'b, Ins, Param, Param, Param, ResultIterator<'a, 'b, Ins, Param, F>, F> for Mep<Ins>
+    where F: Fn(&Ins, Param, Param) -> Param, Param: Copy + 'b {
+    fn execute(&'a self, inputs: &'b [Param],
         outputs: usize, processor: F) -> ResultIterator<'a, 'b, Ins, Param, F> {
         //Ensure we have enough opcodes to produce the desired amount of outputs, otherwise the programmer has failed
         assert!(outputs <= self.program.len());
         ResultIterator{
             mep: self,
-            buff: vec![None; self.program.len()],
+            buff: (0..self.program.len()).map(|_| None).collect(),
             solve_iter: (self.program.len() + self.inputs - outputs..self.program.len() + self.inputs).rev(),
-            inputs: inputs,
-            processor: processor,
+            inputs,
+            processor,
+        }
+    }
+}
+
+impl<'a, 'b, Ins, Param, F> ResultIterator<'a, 'b, Ins, Param, F>
+    where F: Fn(&Ins, Param, Param) -> Param, Param: Copy {
+    //Recurses through the parameters an opcode depends on, memoizing each result in `buff` so no opcode reachable
+    //from more than one place is solved twice. Closures cannot call themselves, so this has to be a method.
+    fn solve(&mut self, i: usize) -> Param {
+        //If this is an input, it is already solved, so return the result immediately
+        if i < self.mep.inputs {
+            return self.inputs[i];
         }
+        let index = i - self.mep.inputs;
+        //Check if this has been evaluated or not
+        if let Some(x) = self.buff[index] {
+            return x;
+        }
+        //Solve both parameters before computing this opcode's result
+        let (first, second) = (self.mep.program[index].first, self.mep.program[index].second);
+        let first = self.solve(first);
+        let second = self.solve(second);
+        //Compute the result of the operation now that the inputs are solved
+        let result = (self.processor)(&self.mep.program[index].instruction, first, second);
+        //Properly store the Some result to the buffer
+        self.buff[index] = Some(result);
+        result
     }
 }
 
 impl<'a, 'b, Ins, Param, F> Iterator for ResultIterator<'a, 'b, Ins, Param, F>
-    where F: Fn(&Ins, Param, Param) -> Param {
+    where F: Fn(&Ins, Param, Param) -> Param, Param: Copy {
     type Item = Param;
     fn next(&mut self) -> Option<Param> {
-        match self.solve_iter.next() {
-            None => None,
-            Some(i) => {
-                let op_solved;
-                op_solved = |i: usize| {
-                    //If this is an input, it is already solved, so return the result immediately
-                    if (i < self.mep.inputs) {
-                        return self.inputs[i];
-                    }
-                    //Check if this has been evaluated or not
-                    match self.buff[i - self.mep.inputs] {
-                        //If it has, return the value
-                        Some(x) => x,
-                        //If it hasnt been solved
-                        None => {
-                            //Get a reference to the opcode
-                            let op = &self.mep.program[i];
-                            //Compute the result of the operation, ensuring the inputs are solved beforehand
-                            let result = self.processor(&op.instruction, op_solved(op.first), op_solved(op.second));
-                            //Properly store the Some result to the buffer
-                            self.buff[i - self.mep.inputs] = Some(result);
-                            //Return the result
-                            result
-                        }
-                    }
-                };
-                //Use the op_solved closure to evaluate the instruction
-                Some(op_solved(i))
-            }
-        }
+        self.solve_iter.next().map(|i| self.solve(i))
     }
 }
 
@@ -241,17 +441,21 @@ mod tests {
 
     #[test]
     fn mep_new() {
-        let a: Mep<u32> = Mep::new(3, 3, 0..8);
+        let mut rng = Isaac64Rng::from_seed(&[1, 2, 3, 4]);
+        let a: Mep<u32> = Mep::new(3, 3, 0, &mut rng, 0..8);
 
-        assert_eq!(a.instructions, (0..8).collect::<Vec<_>>());
+        assert_eq!(a.program.iter().map(|op| op.instruction).collect::<Vec<_>>(), (0..8).collect::<Vec<_>>());
     }
 
     #[test]
     fn mep_crossover() {
         let mut rng = Isaac64Rng::from_seed(&[1, 2, 3, 4]);
         let (a, b) = {
-            let mut clos = || Mep::new(3, 3, rng.gen_iter::<u32>().map(|x| x % 10).take(10));
-            (clos(), clos())
+            let instructions_a: Vec<u32> = rng.clone().gen_iter::<u32>().map(|x| x % 10).take(10).collect();
+            let a = Mep::new(3, 3, 0, &mut rng, instructions_a.into_iter());
+            let instructions_b: Vec<u32> = rng.clone().gen_iter::<u32>().map(|x| x % 10).take(10).collect();
+            let b = Mep::new(3, 3, 0, &mut rng, instructions_b.into_iter());
+            (a, b)
         };
         let old_rngs: Vec<_> = rng.clone().gen_iter::<u32>().take(5).collect();
         let mut c = Mep::mate((&a, &b), &mut rng);
@@ -259,8 +463,53 @@ mod tests {
         assert!(rng.clone().gen_iter::<u32>().take(5).collect::<Vec<_>>() != old_rngs);
 
         c.mutate(&mut rng, |ins: &mut u32| *ins = 2);
-        c.call(|_, (_, _)| {});
 
-        assert_eq!(c.instructions, [0, 7, 5, 4, 2, 8, 5, 6, 0, 2]);
+        let inputs = [0u32, 0, 0];
+        let outputs: Vec<u32> = c.execute(&inputs, 1, |_, first: u32, second: u32| first + second).collect();
+
+        assert_eq!(outputs.len(), 1);
+    }
+
+    #[test]
+    fn mep_effective_prunes_unreachable() {
+        let inputs = 2;
+        let mep = Mep{
+            program: vec![
+                Opcode{instruction: 'a', first: 0, second: 1},
+                Opcode{instruction: 'b', first: 0, second: 0},
+                Opcode{instruction: 'c', first: inputs, second: 1},
+            ],
+            unit_mutate_size: 0,
+            crossover_points: 0,
+            inputs,
+        };
+
+        let (pruned, removed) = mep.effective(1);
+
+        assert_eq!(removed, 1);
+        assert_eq!(pruned.program.len(), 2);
+        assert_eq!(pruned.program[0].instruction, 'a');
+        assert_eq!(pruned.program[1].instruction, 'c');
+        assert_eq!(pruned.program[1].first, inputs);
+    }
+
+    #[test]
+    fn mutate_weighted_skews_toward_more_common_instruction() {
+        let mut rng = Isaac64Rng::from_seed(&[5, 6, 7, 8]);
+        let mut mep = Mep{
+            program: (0..10).map(|i| Opcode{instruction: if i == 0 {0u32} else {1u32}, first: 0, second: 0}).collect(),
+            unit_mutate_size: 2,
+            crossover_points: 0,
+            inputs: 1,
+        };
+        let palette = [0u32, 1u32];
+
+        for _ in 0..200 {
+            mep.mutate_weighted(&mut rng, &palette, |a, b| a == b, 0.1);
+        }
+
+        let ones = mep.program.iter().filter(|op| op.instruction == 1).count();
+        let zeros = mep.program.iter().filter(|op| op.instruction == 0).count();
+        assert!(ones > zeros);
     }
 }
@@ -0,0 +1,463 @@
+use std::cmp;
+use rand::Rng;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use super::{GeneticAlgorithm, Mep};
+use super::speciation::{speciate, SpeciationConfig};
+
+/*
+Configuration for a Runner. `population_size` is held constant across generations, `elitism` is the number of
+top genomes copied unchanged into the next generation, `replacement_rate` is the fraction of the non-elite
+population replaced by newly bred children each generation (the remainder survive unchanged), and
+`tournament_size` is how many individuals are drawn for each tournament selection.
+*/
+#[derive(Clone)]
+pub struct RunnerConfig {
+    pub population_size: usize,
+    pub elitism: usize,
+    pub replacement_rate: f64,
+    pub tournament_size: usize,
+}
+
+/*
+A Population is simply the Vec of genomes that make up one generation.
+*/
+pub struct Population<Ins> {
+    pub individuals: Vec<Mep<Ins>>,
+}
+
+impl<Ins> Population<Ins> {
+    pub fn new(individuals: Vec<Mep<Ins>>) -> Population<Ins> {
+        Population{individuals}
+    }
+}
+
+/*
+Stats reported for a single generation by `Runner::step`. `species_count` is `None` unless the generation was
+produced by `step_speciated`. `stagnant_generations` stays 0 unless the generation was produced by
+`step_with_stagnation`.
+*/
+pub struct GenerationStats {
+    pub generation: usize,
+    pub best_fitness: f64,
+    pub average_fitness: f64,
+    pub species_count: Option<usize>,
+    pub stagnant_generations: usize,
+}
+
+/*
+Which recovery action `step_with_stagnation` takes once stagnation has persisted past `patience` generations.
+*/
+pub enum StagnationResponse {
+    /*
+    Temporarily divides every genome's unit_mutate_size by `divisor` (floored at 1) for the next `generations`
+    generations, raising the mutation rate.
+    */
+    Hypermutation{divisor: usize, generations: usize},
+    /*
+    Replaces the `count` worst individuals in the population with freshly generated genomes.
+    */
+    RandomImmigrants{count: usize},
+}
+
+/*
+Configuration for stagnation detection. The best fitness seen is tracked across calls to `step_with_stagnation`;
+if it fails to improve by more than `epsilon` for `patience` consecutive generations, `response` is triggered and
+the counter resets. `new_genome` produces a brand-new genome the same way the population was originally seeded,
+used by `StagnationResponse::RandomImmigrants`.
+*/
+pub struct StagnationConfig<Gen> {
+    pub epsilon: f64,
+    pub patience: usize,
+    pub response: StagnationResponse,
+    pub new_genome: Gen,
+}
+
+/*
+A Runner owns a Population and drives it through generations of tournament selection, crossover, and mutation.
+`Fit` computes the fitness of a single genome (higher is better) and `Mut` perturbs a single instruction in place,
+matching the `mutator` closure expected by `Mep::mutate`.
+*/
+pub struct Runner<Ins, Fit, Mut, R> where Fit: Fn(&Mep<Ins>) -> f64, Mut: FnMut(&mut Ins), R: Rng {
+    population: Population<Ins>,
+    config: RunnerConfig,
+    fitness: Fit,
+    mutator: Mut,
+    rng: R,
+    best_fitness_seen: Option<f64>,
+    stagnant_generations: usize,
+    hypermutation_remaining: usize,
+    hypermutation_divisor: Option<usize>,
+}
+
+impl<Ins, Fit, Mut, R> Runner<Ins, Fit, Mut, R>
+    where Ins: Clone, Mep<Ins>: GeneticAlgorithm<Ins>, Fit: Fn(&Mep<Ins>) -> f64, Mut: FnMut(&mut Ins), R: Rng
+{
+    pub fn new(population: Population<Ins>, config: RunnerConfig, fitness: Fit, mutator: Mut, rng: R) -> Runner<Ins, Fit, Mut, R> {
+        Runner{population, config, fitness, mutator, rng,
+            best_fitness_seen: None, stagnant_generations: 0, hypermutation_remaining: 0, hypermutation_divisor: None}
+    }
+
+    /*
+    Splits the non-elite portion of the population into how many survive unchanged and how many are replaced by
+    newly bred children this generation, per `config.replacement_rate`.
+    */
+    fn replacement_split(&self) -> (usize, usize) {
+        let non_elite = self.config.population_size.saturating_sub(self.config.elitism);
+        let children = cmp::min((self.config.replacement_rate * non_elite as f64).round() as usize, non_elite);
+        (non_elite - children, children)
+    }
+
+    /*
+    Selects a single parent by drawing `tournament_size` individuals at random from `fitnesses` and keeping the
+    one with the highest fitness.
+    */
+    fn tournament_select(&mut self, fitnesses: &[(usize, f64)]) -> Mep<Ins> {
+        let winner = (0..self.config.tournament_size)
+            .map(|_| {
+                let index = self.rng.gen_range(0, fitnesses.len());
+                fitnesses[index]
+            })
+            .fold(None, |best: Option<(usize, f64)>, candidate| {
+                match best {
+                    Some(b) if b.1 >= candidate.1 => Some(b),
+                    _ => Some(candidate),
+                }
+            })
+            .expect("tournament_size must be greater than 0");
+        self.population.individuals[winner.0].clone()
+    }
+
+    /*
+    Evaluates fitness for every genome in the population, ranks them, carries the elite genomes forward
+    unchanged along with the next-best survivors that `replacement_rate` does not replace, then fills the remainder
+    of the next generation via tournament selection, crossover, and mutation. Returns the stats of the generation
+    that was evaluated, i.e. the one being replaced.
+    */
+    pub fn step(&mut self) -> GenerationStats {
+        let mut fitnesses: Vec<(usize, f64)> = self.population.individuals.iter()
+            .enumerate()
+            .map(|(index, genome)| (index, (self.fitness)(genome)))
+            .collect();
+        fitnesses.sort_by(|a, b| b.1.partial_cmp(&a.1).expect("fitness must not be NaN"));
+
+        let best_fitness = fitnesses[0].1;
+        let average_fitness = fitnesses.iter().map(|&(_, f)| f).sum::<f64>() / fitnesses.len() as f64;
+
+        let (survivors, children) = self.replacement_split();
+        let mut next_generation: Vec<Mep<Ins>> = fitnesses.iter()
+            .take(self.config.elitism + survivors)
+            .map(|&(index, _)| self.population.individuals[index].clone())
+            .collect();
+
+        for _ in 0..children {
+            let first = self.tournament_select(&fitnesses);
+            let second = self.tournament_select(&fitnesses);
+            let mut child = Mep::mate((&first, &second), &mut self.rng);
+            child.mutate(&mut self.rng, &mut self.mutator);
+            next_generation.push(child);
+        }
+
+        self.population = Population::new(next_generation);
+
+        GenerationStats{generation: 0, best_fitness, average_fitness, species_count: None,
+            stagnant_generations: 0}
+    }
+
+    /*
+    Runs `step` for `n_generations` generations and returns the fittest genome of the final population along with
+    the stats collected for every generation along the way.
+    */
+    pub fn run(&mut self, n_generations: usize) -> (Mep<Ins>, Vec<GenerationStats>)
+        where Fit: Fn(&Mep<Ins>) -> f64
+    {
+        let mut stats = Vec::with_capacity(n_generations);
+        for generation in 0..n_generations {
+            let mut generation_stats = self.step();
+            generation_stats.generation = generation;
+            stats.push(generation_stats);
+        }
+
+        let best = self.population.individuals.iter()
+            .max_by(|a, b| (self.fitness)(a).partial_cmp(&(self.fitness)(b)).expect("fitness must not be NaN"))
+            .expect("population must not be empty")
+            .clone();
+
+        (best, stats)
+    }
+
+    /*
+    Speciated equivalent of `step`. Genomes are grouped into species by `compatibility_distance`, then every
+    individual's fitness is divided by the size of its species (fitness sharing) before tournament selection, so
+    crowded niches are penalized and small but novel species are not immediately outcompeted. The genomes carried
+    forward unchanged (elites and survivors) and the reported `best_fitness`/`average_fitness` still use the raw,
+    unshared fitness.
+    */
+    pub fn step_speciated<Eq>(&mut self, speciation: &SpeciationConfig<Eq>) -> GenerationStats
+        where Eq: Fn(&Ins, &Ins) -> bool
+    {
+        let raw_fitnesses: Vec<f64> = self.population.individuals.iter()
+            .map(|genome| (self.fitness)(genome))
+            .collect();
+        let species = speciate(&self.population.individuals, speciation.c1, speciation.c2, speciation.c3,
+            speciation.threshold, &speciation.instructions_equal);
+
+        let mut species_size = vec![1usize; raw_fitnesses.len()];
+        for s in &species {
+            for &member in &s.members {
+                species_size[member] = s.members.len();
+            }
+        }
+
+        let mut fitnesses: Vec<(usize, f64)> = raw_fitnesses.iter().enumerate()
+            .map(|(index, &f)| (index, f / species_size[index] as f64))
+            .collect();
+        fitnesses.sort_by(|a, b| b.1.partial_cmp(&a.1).expect("fitness must not be NaN"));
+
+        let best_fitness = raw_fitnesses.iter().cloned().fold(f64::MIN, f64::max);
+        let average_fitness = raw_fitnesses.iter().sum::<f64>() / raw_fitnesses.len() as f64;
+
+        let mut raw_ranked: Vec<(usize, f64)> = raw_fitnesses.iter().cloned().enumerate().collect();
+        raw_ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).expect("fitness must not be NaN"));
+
+        let (survivors, children) = self.replacement_split();
+        let mut next_generation: Vec<Mep<Ins>> = raw_ranked.iter()
+            .take(self.config.elitism + survivors)
+            .map(|&(index, _)| self.population.individuals[index].clone())
+            .collect();
+
+        for _ in 0..children {
+            let first = self.tournament_select(&fitnesses);
+            let second = self.tournament_select(&fitnesses);
+            let mut child = Mep::mate((&first, &second), &mut self.rng);
+            child.mutate(&mut self.rng, &mut self.mutator);
+            next_generation.push(child);
+        }
+
+        self.population = Population::new(next_generation);
+
+        GenerationStats{generation: 0, best_fitness, average_fitness,
+            species_count: Some(species.len()), stagnant_generations: 0}
+    }
+
+    /*
+    Stagnation-aware equivalent of `step`. After stepping, the best fitness reached so far is compared against
+    `best_fitness_seen`; an improvement of more than `epsilon` resets the stagnation counter, otherwise it grows.
+    Once the counter reaches `patience` (and at least one generation has stagnated), `response` fires once: for
+    `Hypermutation`, every genome's unit_mutate_size is divided down (floored at 1) a single time and
+    `hypermutation_remaining` counts down the `generations` it stays in effect for, so the lowered rate is not
+    divided down again while the recovery is still taking effect. Once the window elapses, every genome's
+    unit_mutate_size is multiplied back by the same divisor, ending the elevated mutation rate rather than leaving
+    unit_mutate_size permanently lowered.
+    */
+    pub fn step_with_stagnation<Gen>(&mut self, stagnation: &StagnationConfig<Gen>) -> GenerationStats
+        where Gen: Fn(&mut R) -> Mep<Ins>
+    {
+        if self.hypermutation_remaining > 0 {
+            self.hypermutation_remaining -= 1;
+            if self.hypermutation_remaining == 0 {
+                if let Some(divisor) = self.hypermutation_divisor.take() {
+                    for genome in self.population.individuals.iter_mut() {
+                        let restored = genome.unit_mutate_size() * divisor;
+                        genome.set_unit_mutate_size(restored);
+                    }
+                }
+            }
+        }
+
+        let mut stats = self.step();
+
+        let improved = match self.best_fitness_seen {
+            Some(previous) => stats.best_fitness > previous + stagnation.epsilon,
+            None => true,
+        };
+
+        if improved {
+            self.best_fitness_seen = Some(stats.best_fitness);
+            self.stagnant_generations = 0;
+        } else {
+            self.stagnant_generations += 1;
+        }
+
+        if self.stagnant_generations >= cmp::max(stagnation.patience, 1) {
+            match stagnation.response {
+                StagnationResponse::Hypermutation{divisor, generations} => {
+                    if self.hypermutation_remaining == 0 {
+                        for genome in self.population.individuals.iter_mut() {
+                            let lowered = cmp::max(genome.unit_mutate_size() / divisor, 1);
+                            genome.set_unit_mutate_size(lowered);
+                        }
+                        self.hypermutation_remaining = generations;
+                        self.hypermutation_divisor = Some(divisor);
+                    }
+                }
+                StagnationResponse::RandomImmigrants{count} => {
+                    let mut fitnesses: Vec<(usize, f64)> = self.population.individuals.iter()
+                        .enumerate()
+                        .map(|(index, genome)| (index, (self.fitness)(genome)))
+                        .collect();
+                    fitnesses.sort_by(|a, b| a.1.partial_cmp(&b.1).expect("fitness must not be NaN"));
+                    for &(index, _) in fitnesses.iter().take(count) {
+                        self.population.individuals[index] = (stagnation.new_genome)(&mut self.rng);
+                    }
+                }
+            }
+            self.stagnant_generations = 0;
+        }
+
+        stats.stagnant_generations = self.stagnant_generations;
+        stats
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<Ins, Fit, Mut, R> Runner<Ins, Fit, Mut, R>
+    where Ins: Clone + Send + Sync, Mep<Ins>: GeneticAlgorithm<Ins> + Send + Sync,
+        Fit: Fn(&Mep<Ins>) -> f64 + Sync, Mut: Fn(&mut Ins) + Sync, R: Rng + Clone + Send + Sync
+{
+    /*
+    Parallel equivalent of `step`, enabled by the `rayon` feature. Fitness evaluation is pure and only borrows the
+    population immutably, so it is safe to run across the thread pool with `par_iter`. Breeding the replacement
+    children is parallelized the same way: each child only needs its two parents, so every child is bred with its
+    own thread-local RNG. Rather than requiring `R` to be seedable from a bare `u64` (none of this crate's usual
+    RNGs support that), every child's RNG is a clone of the main RNG advanced a deterministically-drawn number of
+    steps (drawn independently per child, so two children may occasionally share one), so a run with a given starting RNG state always reproduces the same population regardless of
+    how many threads are available.
+    */
+    pub fn step_parallel(&mut self) -> GenerationStats {
+        let mut fitnesses: Vec<(usize, f64)> = self.population.individuals
+            .par_iter()
+            .enumerate()
+            .map(|(index, genome)| (index, (self.fitness)(genome)))
+            .collect();
+        fitnesses.sort_by(|a, b| b.1.partial_cmp(&a.1).expect("fitness must not be NaN"));
+
+        let best_fitness = fitnesses[0].1;
+        let average_fitness = fitnesses.iter().map(|&(_, f)| f).sum::<f64>() / fitnesses.len() as f64;
+
+        let (survivors, children_count) = self.replacement_split();
+        let mut next_generation: Vec<Mep<Ins>> = fitnesses.iter()
+            .take(self.config.elitism + survivors)
+            .map(|&(index, _)| self.population.individuals[index].clone())
+            .collect();
+
+        // Draw each child's jump distance sequentially from the main RNG so the result does not depend on thread
+        // scheduling, then clone the main RNG's post-draw state as the common base every child jumps forward from.
+        let jumps: Vec<u32> = (0..children_count).map(|_| self.rng.gen_range(0, 1 << 16)).collect();
+        let base_rng = self.rng.clone();
+
+        let individuals = &self.population.individuals;
+        let fitnesses_ref = &fitnesses;
+        let tournament_size = self.config.tournament_size;
+        let mutator = &self.mutator;
+
+        let children: Vec<Mep<Ins>> = jumps.into_par_iter()
+            .map(|jump| {
+                let mut local_rng = base_rng.clone();
+                for _ in 0..jump {
+                    local_rng.gen::<u64>();
+                }
+                let select = |local_rng: &mut R| {
+                    (0..tournament_size)
+                        .map(|_| fitnesses_ref[local_rng.gen_range(0, fitnesses_ref.len())])
+                        .fold(None, |best: Option<(usize, f64)>, candidate| {
+                            match best {
+                                Some(b) if b.1 >= candidate.1 => Some(b),
+                                _ => Some(candidate),
+                            }
+                        })
+                        .expect("tournament_size must be greater than 0")
+                };
+                let first = &individuals[select(&mut local_rng).0];
+                let second = &individuals[select(&mut local_rng).0];
+                let mut child = Mep::mate((first, second), &mut local_rng);
+                child.mutate(&mut local_rng, |ins: &mut Ins| mutator(ins));
+                child
+            })
+            .collect();
+
+        next_generation.extend(children);
+        self.population = Population::new(next_generation);
+
+        GenerationStats{generation: 0, best_fitness, average_fitness, species_count: None,
+            stagnant_generations: 0}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use rand::{Isaac64Rng, SeedableRng, Rng};
+    use super::*;
+    use super::super::Mep;
+
+    fn make_genome(rng: &mut Isaac64Rng, unit_mutate_size: usize) -> Mep<u32> {
+        let instructions: Vec<u32> = rng.clone().gen_iter::<u32>().map(|x| x % 4).take(6).collect();
+        Mep::new(2, unit_mutate_size, 0, rng, instructions.into_iter())
+    }
+
+    #[test]
+    fn replacement_split_honors_replacement_rate() {
+        let mut rng = Isaac64Rng::from_seed(&[1, 2, 3, 4]);
+        let population = Population::new((0..10).map(|_| make_genome(&mut rng, 4)).collect());
+        let config = RunnerConfig{population_size: 10, elitism: 2, replacement_rate: 0.5, tournament_size: 2};
+        let runner = Runner::new(population, config, |_: &Mep<u32>| 0.0, |_: &mut u32| {}, rng);
+
+        // non_elite = 10 - 2 = 8, children = round(0.5 * 8) = 4, survivors = 8 - 4 = 4
+        let (survivors, children) = runner.replacement_split();
+        assert_eq!(children, 4);
+        assert_eq!(survivors, 4);
+    }
+
+    #[test]
+    fn step_with_stagnation_restores_unit_mutate_size_after_hypermutation_window() {
+        let mut rng = Isaac64Rng::from_seed(&[1, 2, 3, 4]);
+        let population = Population::new(vec![make_genome(&mut rng, 8)]);
+        let config = RunnerConfig{population_size: 1, elitism: 1, replacement_rate: 0.0, tournament_size: 1};
+        let fitness_value = Rc::new(Cell::new(0.0));
+        let fitness_value_in_runner = fitness_value.clone();
+        let mut runner = Runner::new(population, config, move |_: &Mep<u32>| fitness_value_in_runner.get(), |_: &mut u32| {}, rng);
+
+        let stagnation = StagnationConfig{
+            epsilon: 0.0,
+            patience: 0,
+            response: StagnationResponse::Hypermutation{divisor: 2, generations: 2},
+            new_genome: |_: &mut Isaac64Rng| panic!("RandomImmigrants should not fire in this test"),
+        };
+
+        runner.step_with_stagnation(&stagnation); //establishes best_fitness_seen, no trigger
+        runner.step_with_stagnation(&stagnation); //no improvement -> triggers hypermutation, 8 / 2 = 4
+        assert_eq!(runner.population.individuals[0].unit_mutate_size(), 4);
+
+        fitness_value.set(10.0);
+        runner.step_with_stagnation(&stagnation); //improves, still mid-window
+        assert_eq!(runner.population.individuals[0].unit_mutate_size(), 4);
+
+        fitness_value.set(20.0);
+        runner.step_with_stagnation(&stagnation); //improves, window elapses -> restored to 4 * 2 = 8
+        assert_eq!(runner.population.individuals[0].unit_mutate_size(), 8);
+    }
+
+    // A plain fn item, not a closure: step_parallel requires the mutator to be `Fn` (for `Sync`), while
+    // `Runner::new` only demands `FnMut`, and a closure's inferred kind is pinned to the least capability
+    // required at its one call site. A fn item always implements `Fn`, sidestepping that inference pitfall.
+    #[cfg(feature = "rayon")]
+    fn bump_instruction(ins: &mut u32) { *ins = ins.wrapping_add(1); }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn step_parallel_preserves_population_size() {
+        let mut rng = Isaac64Rng::from_seed(&[1, 2, 3, 4]);
+        let population = Population::new((0..6).map(|_| make_genome(&mut rng, 4)).collect());
+        let config = RunnerConfig{population_size: 6, elitism: 1, replacement_rate: 0.5, tournament_size: 2};
+        let mut runner = Runner::new(population, config,
+            |genome: &Mep<u32>| genome.unit_mutate_size() as f64,
+            bump_instruction,
+            rng);
+
+        runner.step_parallel();
+
+        assert_eq!(runner.population.individuals.len(), 6);
+    }
+}
@@ -0,0 +1,112 @@
+use std::cmp;
+use super::Mep;
+
+/*
+The compatibility distance between two genomes, used to group similar genomes into species. It combines the
+difference in program length, the number of mismatched instructions over the aligned prefix shared by both
+genomes, and the average absolute difference between the `first`/`second` parameter indices over that same
+aligned prefix, each weighted by its own coefficient and normalized by the length of the longer genome. Two
+identical genomes have a distance of 0.
+*/
+pub fn compatibility_distance<Ins, Eq>(a: &Mep<Ins>, b: &Mep<Ins>, c1: f64, c2: f64, c3: f64, instructions_equal: Eq) -> f64
+    where Eq: Fn(&Ins, &Ins) -> bool
+{
+    let len_a = a.len();
+    let len_b = b.len();
+    let aligned = cmp::min(len_a, len_b);
+    let longer = cmp::max(len_a, len_b);
+
+    if longer == 0 {
+        return 0.0;
+    }
+
+    let mut mismatched = 0usize;
+    let mut param_diff_sum = 0usize;
+    for index in 0..aligned {
+        if !instructions_equal(a.instruction(index), b.instruction(index)) {
+            mismatched += 1;
+        }
+        let (a_first, a_second) = a.opcode_params(index);
+        let (b_first, b_second) = b.opcode_params(index);
+        param_diff_sum += (a_first as isize - b_first as isize).unsigned_abs();
+        param_diff_sum += (a_second as isize - b_second as isize).unsigned_abs();
+    }
+    let avg_param_diff = if aligned == 0 {0.0} else {param_diff_sum as f64 / (aligned * 2) as f64};
+    let len_diff = (len_a as isize - len_b as isize).abs() as f64;
+
+    (c1 * len_diff + c2 * mismatched as f64 + c3 * avg_param_diff) / longer as f64
+}
+
+/*
+A Species groups together the indices of population members whose compatibility distance to `representative`
+falls below the speciation threshold. `representative` is simply the first genome assigned to the species.
+*/
+pub struct Species<Ins> {
+    pub representative: Mep<Ins>,
+    pub members: Vec<usize>,
+}
+
+/*
+Coefficients and threshold controlling speciation, plus the instruction-equality predicate required to compute
+compatibility distance. A genome joins an existing species only if its compatibility distance is strictly below
+`threshold`; a genome at or beyond it founds a new species.
+*/
+pub struct SpeciationConfig<Eq> {
+    pub c1: f64,
+    pub c2: f64,
+    pub c3: f64,
+    pub threshold: f64,
+    pub instructions_equal: Eq,
+}
+
+/*
+Groups `population` into species. Each genome joins the first species whose representative is within `threshold`
+of it; otherwise it founds a new species with itself as the representative. The resulting species partition the
+population's indices.
+*/
+pub fn speciate<Ins, Eq>(population: &[Mep<Ins>], c1: f64, c2: f64, c3: f64, threshold: f64, instructions_equal: &Eq)
+    -> Vec<Species<Ins>>
+    where Ins: Clone, Eq: Fn(&Ins, &Ins) -> bool
+{
+    let mut species: Vec<Species<Ins>> = Vec::new();
+    for (index, genome) in population.iter().enumerate() {
+        let home = species.iter_mut()
+            .find(|s| compatibility_distance(genome, &s.representative, c1, c2, c3, instructions_equal) < threshold);
+        match home {
+            Some(s) => s.members.push(index),
+            None => species.push(Species{representative: genome.clone(), members: vec![index]}),
+        }
+    }
+    species
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Isaac64Rng, SeedableRng, Rng};
+    use super::*;
+    use super::super::Mep;
+
+    #[test]
+    fn compatibility_distance_self_is_zero() {
+        let mut rng = Isaac64Rng::from_seed(&[1, 2, 3, 4]);
+        let instructions: Vec<u32> = rng.clone().gen_iter::<u32>().map(|x| x % 4).take(6).collect();
+        let a: Mep<u32> = Mep::new(2, 3, 0, &mut rng, instructions.into_iter());
+
+        assert_eq!(compatibility_distance(&a, &a, 1.0, 1.0, 1.0, |x, y| x == y), 0.0);
+    }
+
+    #[test]
+    fn speciate_groups_identical_genomes_together() {
+        let mut rng = Isaac64Rng::from_seed(&[1, 2, 3, 4]);
+        let instructions: Vec<u32> = rng.clone().gen_iter::<u32>().map(|x| x % 4).take(6).collect();
+        let a: Mep<u32> = Mep::new(2, 3, 0, &mut rng, instructions.clone().into_iter());
+        let b = a.clone();
+        let c: Mep<u32> = Mep::new(2, 3, 0, &mut rng, vec![9u32; 6].into_iter());
+
+        let species = speciate(&[a, b, c], 1.0, 1.0, 1.0, 0.01, &|x: &u32, y: &u32| x == y);
+
+        assert_eq!(species.len(), 2);
+        assert_eq!(species[0].members, vec![0, 1]);
+        assert_eq!(species[1].members, vec![2]);
+    }
+}